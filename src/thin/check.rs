@@ -1,13 +1,13 @@
 use anyhow::{anyhow, Result};
 use nom::{bytes::complete::*, number::complete::*, IResult};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use crate::block_manager::{Block, IoEngine, AsyncIoEngine, SyncIoEngine, BLOCK_SIZE};
+use crate::block_manager::{AsyncIoEngine, Block, IoEngine, SyncIoEngine, BLOCK_SIZE};
 use crate::checksum;
 use crate::thin::superblock::*;
 
@@ -67,7 +67,17 @@ fn unpack_node_<V: ValueType>(data: &[u8]) -> IResult<&[u8], Node<V>> {
     let (i, header) = unpack_node_header(data)?;
     let (i, keys) = count(le_u64, header.nr_entries as usize)(i)?;
 
-    let nr_free = header.max_entries - header.nr_entries;
+    // A corrupt header can claim more entries than the node has room for;
+    // bail out of the parse rather than underflowing this subtraction.
+    let nr_free = match header.max_entries.checked_sub(header.nr_entries) {
+        Some(n) => n,
+        None => {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                i,
+                nom::error::ErrorKind::Verify,
+            )))
+        }
+    };
     let (i, _padding) = count(le_u64, nr_free as usize)(i)?;
 
     if header.is_leaf {
@@ -140,78 +150,751 @@ enum MappingLevel {
     Bottom,
 }
 
-fn walk_nodes<E: IoEngine>(
-    engine: &mut E,
-    seen: &mut HashSet<u64>,
-    level: MappingLevel,
-    bs: &Vec<u64>,
+// A unit of pending work: a block to read plus enough context to unpack
+// and file it away once it comes back.  Bottom-level blocks carry the
+// thin device id their subtree belongs to, so leaves can be tallied per
+// device.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum WalkItem {
+    Top(u64),
+    Bottom { dev_id: u64, block: u64 },
+}
+
+impl WalkItem {
+    fn block(&self) -> u64 {
+        match self {
+            WalkItem::Top(b) => *b,
+            WalkItem::Bottom { block, .. } => *block,
+        }
+    }
+}
+
+// Bumps the reference count of `b`, regardless of whether it has already
+// been visited.  A block shared between snapshots is referenced by more
+// than one leaf, and the space map is expected to record that.
+fn bump_ref_count(counts: &mut HashMap<u64, u32>, b: u64) {
+    *counts.entry(b).or_insert(0) += 1;
+}
+
+// A single corruption found while walking the mapping tree.  Rather than
+// bailing out of the walk on the first one, callers accumulate these so
+// a user can see every problem in one pass instead of fixing-and-rerunning.
+#[derive(Debug)]
+enum ErrorKind {
+    BadChecksum,
+    CouldntUnpackNode,
+    TooManyEntries { nr_entries: u32, max_entries: u32 },
+    KeysNotOrdered,
+    DataBlockOutOfBounds { nr_data_blocks: u64 },
+}
+
+#[derive(Debug)]
+struct MetadataError {
+    block: u64,
+    key: Option<u64>,
+    value: Option<u64>,
+    kind: ErrorKind,
+}
+
+impl MetadataError {
+    fn new(block: u64, kind: ErrorKind) -> Self {
+        MetadataError {
+            block,
+            key: None,
+            value: None,
+            kind,
+        }
+    }
+
+    fn with_key(mut self, key: u64) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    fn with_value(mut self, value: u64) -> Self {
+        self.value = Some(value);
+        self
+    }
+}
+
+// Peeks at a node's header before attempting a full unpack, so a corrupt
+// nr_entries/max_entries pair is reported precisely instead of just
+// showing up as "couldn't unpack" once the body parse fails.
+fn check_header_invariants(block: u64, data: &[u8]) -> Option<MetadataError> {
+    let (_, header) = unpack_node_header(data).ok()?;
+    if header.nr_entries > header.max_entries {
+        Some(MetadataError::new(
+            block,
+            ErrorKind::TooManyEntries {
+                nr_entries: header.nr_entries,
+                max_entries: header.max_entries,
+            },
+        ))
+    } else {
+        None
+    }
+}
+
+// Keys within a node must be strictly increasing.
+fn check_keys_ordered(block: u64, keys: &[u64], errors: &mut Vec<MetadataError>) {
+    for w in keys.windows(2) {
+        if w[0] >= w[1] {
+            errors.push(MetadataError::new(block, ErrorKind::KeysNotOrdered).with_key(w[1]));
+            break;
+        }
+    }
+}
+
+// Every data block a bottom-level leaf points at must fall within the
+// pool's data device.
+fn check_data_block_bounds(
+    block: u64,
+    keys: &[u64],
+    values: &[BlockTime],
+    nr_data_blocks: u64,
+    errors: &mut Vec<MetadataError>,
+) {
+    for (k, v) in keys.iter().zip(values.iter()) {
+        if v.block >= nr_data_blocks {
+            errors.push(
+                MetadataError::new(block, ErrorKind::DataBlockOutOfBounds { nr_data_blocks })
+                    .with_key(*k)
+                    .with_value(v.block),
+            );
+        }
+    }
+}
+
+// A shared work queue of blocks still to be read and unpacked.  `active`
+// tracks batches that have been popped but not yet finished, so idle
+// workers can tell "nothing left to do" apart from "someone else is
+// about to push more work".
+struct WalkQueue {
+    items: Mutex<VecDeque<WalkItem>>,
+    active: Mutex<usize>,
+}
+
+impl WalkQueue {
+    fn new() -> Self {
+        WalkQueue {
+            items: Mutex::new(VecDeque::new()),
+            active: Mutex::new(0),
+        }
+    }
+
+    fn push_top(&self, bs: &[u64]) {
+        let mut items = self.items.lock().unwrap();
+        for b in bs {
+            items.push_back(WalkItem::Top(*b));
+        }
+    }
+
+    fn push_bottom(&self, dev_id: u64, bs: &[u64]) {
+        let mut items = self.items.lock().unwrap();
+        for b in bs {
+            items.push_back(WalkItem::Bottom { dev_id, block: *b });
+        }
+    }
+
+    // The popped items are counted as active before `items` is unlocked,
+    // so is_idle() never sees an empty queue while they are uncounted.
+    fn pop_batch(&self, max: usize) -> Vec<WalkItem> {
+        let mut items = self.items.lock().unwrap();
+        let n = max.min(items.len());
+        self.enter(n);
+        items.drain(..n).collect()
+    }
+
+    fn enter(&self, n: usize) {
+        *self.active.lock().unwrap() += n;
+    }
+
+    fn leave(&self, n: usize) {
+        *self.active.lock().unwrap() -= n;
+    }
+
+    fn is_idle(&self) -> bool {
+        self.items.lock().unwrap().is_empty() && *self.active.lock().unwrap() == 0
+    }
+}
+
+// Keeps the `n` items that pop_batch() marked as in-flight accounted for
+// until the guard drops.  Using Drop rather than a plain leave call means
+// a batch is still accounted for as finished if processing it panics, so
+// other workers don't spin in is_idle() forever waiting for a thread
+// that's gone.
+struct InFlightGuard<'a> {
+    queue: &'a WalkQueue,
+    n: usize,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn new(queue: &'a WalkQueue, n: usize) -> Self {
+        InFlightGuard { queue, n }
+    }
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.queue.leave(self.n);
+    }
+}
+
+// Raw bytes of a block that's already been read and checksum/header
+// checked, shared by every (dev_id, block) pairing that refers to it.
+// `None` means the block was found corrupt and already reported, so
+// later references to it shouldn't report the same problem again.
+type NodeCache = Mutex<HashMap<u64, Option<Arc<Vec<u8>>>>>;
+
+// Tracks which (dev_id, block) logical references have already had their
+// ref count bumped, so an item that gets requeued while waiting on
+// another thread's read of the same physical block (see the "cache miss"
+// branch below) isn't counted twice when it's dispatched for real.
+type CountedSet = Mutex<HashSet<WalkItem>>;
+
+fn process_batch<E: IoEngine>(
+    engine: &Mutex<E>,
+    seen: &Mutex<HashSet<u64>>,
+    cache: &NodeCache,
+    counted: &CountedSet,
+    counts: &Mutex<HashMap<u64, u32>>,
+    mapped_counts: &Mutex<HashMap<u64, u64>>,
+    errors: &Mutex<Vec<MetadataError>>,
+    nr_data_blocks: u64,
+    queue: &WalkQueue,
+    batch: &[WalkItem],
 ) -> Result<()> {
-    let mut blocks = Vec::new();
-    for b in bs {
-        if !seen.contains(b) {
-            blocks.push(Block::new(*b));
+    let mut to_read = Vec::new();
+    {
+        let mut seen = seen.lock().unwrap();
+        for item in batch {
+            if seen.insert(item.block()) {
+                to_read.push(Block::new(item.block()));
+            }
+        }
+    }
+
+    if !to_read.is_empty() {
+        let mut engine = engine.lock().unwrap();
+        engine.read_many(&mut to_read)?;
+    }
+
+    for b in &to_read {
+        let bt = checksum::metadata_block_type(b.get_data());
+        let entry = if bt != checksum::BT::NODE {
+            errors
+                .lock()
+                .unwrap()
+                .push(MetadataError::new(b.loc, ErrorKind::BadChecksum));
+            None
+        } else if let Some(e) = check_header_invariants(b.loc, &b.get_data()) {
+            errors.lock().unwrap().push(e);
+            None
+        } else {
+            Some(Arc::new(b.get_data().to_vec()))
+        };
+        cache.lock().unwrap().insert(b.loc, entry);
+    }
+
+    // A bottom-level block referenced by more than one thin device (the
+    // normal state of any snapshot) shows up as more than one WalkItem
+    // for the same block number. Each one is dispatched separately here
+    // -- using the cached bytes rather than re-reading -- so every
+    // device's tally and subtree recursion is accounted for, not just
+    // whichever dev_id happened to be read first.
+    for item in batch {
+        let block = item.block();
+        let data = cache.lock().unwrap().get(&block).cloned();
+        let data = match data {
+            Some(Some(d)) => d,
+            Some(None) => {
+                if counted.lock().unwrap().insert(*item) {
+                    bump_ref_count(&mut counts.lock().unwrap(), block);
+                }
+                continue;
+            }
+            None => {
+                // Another thread is still reading this block (it was
+                // marked `seen` before its read completed); requeue and
+                // pick it up again once the cache entry lands. Don't bump
+                // the ref count here -- this same item will come back
+                // through this loop once the read finishes.
+                match item {
+                    WalkItem::Top(b) => queue.push_top(&[*b]),
+                    WalkItem::Bottom { dev_id, block } => queue.push_bottom(*dev_id, &[*block]),
+                }
+                continue;
+            }
+        };
+
+        if counted.lock().unwrap().insert(*item) {
+            bump_ref_count(&mut counts.lock().unwrap(), block);
+        }
+
+        match item {
+            WalkItem::Top(_) => {
+                let node = match unpack_node::<ValueU64>(&data) {
+                    Ok(node) => node,
+                    Err(_) => {
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push(MetadataError::new(block, ErrorKind::CouldntUnpackNode));
+                        continue;
+                    }
+                };
+                match node {
+                    Node::Leaf {
+                        header: _header,
+                        keys,
+                        values,
+                    } => {
+                        let mut local_errors = Vec::new();
+                        check_keys_ordered(block, &keys, &mut local_errors);
+                        if !local_errors.is_empty() {
+                            errors.lock().unwrap().extend(local_errors);
+                        }
+                        for (dev_id, root) in keys.iter().zip(values.iter()) {
+                            queue.push_bottom(*dev_id, &[*root]);
+                        }
+                    }
+                    Node::Internal {
+                        header: _header,
+                        keys,
+                        values,
+                    } => {
+                        let mut local_errors = Vec::new();
+                        check_keys_ordered(block, &keys, &mut local_errors);
+                        if !local_errors.is_empty() {
+                            errors.lock().unwrap().extend(local_errors);
+                        }
+                        queue.push_top(&values);
+                    }
+                }
+            }
+            WalkItem::Bottom { dev_id, .. } => {
+                let dev_id = *dev_id;
+                let node = match unpack_node::<ValueBlockTime>(&data) {
+                    Ok(node) => node,
+                    Err(_) => {
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push(MetadataError::new(block, ErrorKind::CouldntUnpackNode));
+                        continue;
+                    }
+                };
+                match node {
+                    Node::Leaf {
+                        header: _header,
+                        keys,
+                        values,
+                    } => {
+                        let mut local_errors = Vec::new();
+                        check_keys_ordered(block, &keys, &mut local_errors);
+                        check_data_block_bounds(
+                            block,
+                            &keys,
+                            &values,
+                            nr_data_blocks,
+                            &mut local_errors,
+                        );
+
+                        let local_blocks: Vec<u64> = values.iter().map(|v| v.block).collect();
+
+                        {
+                            let mut counts = counts.lock().unwrap();
+                            for blk in local_blocks {
+                                bump_ref_count(&mut counts, blk);
+                            }
+                        }
+                        if !local_errors.is_empty() {
+                            errors.lock().unwrap().extend(local_errors);
+                        }
+                        *mapped_counts.lock().unwrap().entry(dev_id).or_insert(0) +=
+                            values.len() as u64;
+                    }
+                    Node::Internal {
+                        header: _header,
+                        keys,
+                        values,
+                    } => {
+                        let mut local_errors = Vec::new();
+                        check_keys_ordered(block, &keys, &mut local_errors);
+                        if !local_errors.is_empty() {
+                            errors.lock().unwrap().extend(local_errors);
+                        }
+                        queue.push_bottom(dev_id, &values);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn worker_loop<E: IoEngine>(
+    engine: &Mutex<E>,
+    seen: &Mutex<HashSet<u64>>,
+    cache: &NodeCache,
+    counted: &CountedSet,
+    counts: &Mutex<HashMap<u64, u32>>,
+    mapped_counts: &Mutex<HashMap<u64, u64>>,
+    errors: &Mutex<Vec<MetadataError>>,
+    nr_data_blocks: u64,
+    queue: &WalkQueue,
+    error: &Mutex<Option<String>>,
+    queue_depth: usize,
+) {
+    loop {
+        if error.lock().unwrap().is_some() {
+            return;
+        }
+
+        let batch = queue.pop_batch(queue_depth);
+        if batch.is_empty() {
+            if queue.is_idle() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+
+        let _guard = InFlightGuard::new(queue, batch.len());
+        if let Err(e) = process_batch(
+            engine,
+            seen,
+            cache,
+            counted,
+            counts,
+            mapped_counts,
+            errors,
+            nr_data_blocks,
+            queue,
+            &batch,
+        ) {
+            *error.lock().unwrap() = Some(e.to_string());
+        }
+    }
+}
+
+// Walks the mapping tree starting at `root`, spreading the work across
+// `nr_threads` workers that each pull up to `queue_depth` blocks at a
+// time off a shared queue.  Metadata devices spend almost all their time
+// waiting on I/O, so overlapping reads across threads hides most of that
+// latency.  Alongside the usual ref-count bookkeeping this also tallies
+// how many data blocks each thin device has mapped, keyed by device id.
+fn walk_mapping_tree<E: IoEngine + Send + 'static>(
+    engine: E,
+    root: u64,
+    nr_data_blocks: u64,
+    nr_threads: usize,
+    queue_depth: usize,
+) -> Result<(
+    E,
+    HashSet<u64>,
+    HashMap<u64, u32>,
+    HashMap<u64, u64>,
+    Vec<MetadataError>,
+)> {
+    let engine = Arc::new(Mutex::new(engine));
+    let seen = Arc::new(Mutex::new(HashSet::new()));
+    let cache: Arc<NodeCache> = Arc::new(Mutex::new(HashMap::new()));
+    let counted: Arc<CountedSet> = Arc::new(Mutex::new(HashSet::new()));
+    let counts = Arc::new(Mutex::new(HashMap::new()));
+    let mapped_counts = Arc::new(Mutex::new(HashMap::new()));
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let queue = Arc::new(WalkQueue::new());
+    let error = Arc::new(Mutex::new(None));
+
+    queue.push_top(&[root]);
+
+    let mut handles = Vec::new();
+    for _ in 0..nr_threads {
+        let engine = engine.clone();
+        let seen = seen.clone();
+        let cache = cache.clone();
+        let counted = counted.clone();
+        let counts = counts.clone();
+        let mapped_counts = mapped_counts.clone();
+        let errors = errors.clone();
+        let queue = queue.clone();
+        let error = error.clone();
+
+        handles.push(thread::spawn(move || {
+            worker_loop(
+                &engine,
+                &seen,
+                &cache,
+                &counted,
+                &counts,
+                &mapped_counts,
+                &errors,
+                nr_data_blocks,
+                &queue,
+                &error,
+                queue_depth,
+            );
+        }));
+    }
+
+    for h in handles {
+        if let Err(panic) = h.join() {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "worker thread panicked".to_string());
+            *error.lock().unwrap() = Some(msg);
+        }
+    }
+
+    if let Some(e) = error.lock().unwrap().take() {
+        return Err(anyhow!(e));
+    }
+
+    let engine = Arc::try_unwrap(engine).unwrap().into_inner().unwrap();
+    let seen = Arc::try_unwrap(seen).unwrap().into_inner().unwrap();
+    let counts = Arc::try_unwrap(counts).unwrap().into_inner().unwrap();
+    let mapped_counts = Arc::try_unwrap(mapped_counts)
+        .unwrap()
+        .into_inner()
+        .unwrap();
+    let errors = Arc::try_unwrap(errors).unwrap().into_inner().unwrap();
+    Ok((engine, seen, counts, mapped_counts, errors))
+}
+
+// An entry in the metadata space map's index tree: it points at a bitmap
+// block that holds the ref counts for a run of metadata blocks.
+struct SmIndexEntry {
+    blocknr: u64,
+    nr_free: u32,
+    none_free_before: u32,
+}
+
+struct ValueSmIndexEntry;
+
+impl ValueType for ValueSmIndexEntry {
+    type Value = SmIndexEntry;
+    fn unpack(i: &[u8]) -> IResult<&[u8], SmIndexEntry> {
+        let (i, blocknr) = le_u64(i)?;
+        let (i, nr_free) = le_u32(i)?;
+        let (i, none_free_before) = le_u32(i)?;
+
+        Ok((
+            i,
+            SmIndexEntry {
+                blocknr,
+                nr_free,
+                none_free_before,
+            },
+        ))
+    }
+}
+
+// Bitmap blocks start with a 16 byte header (csum, not_used, blocknr),
+// followed by 2-bit ref counts for each block the entry covers.  A count
+// of 3 means "look in the overflow tree", which we don't walk yet.
+const BITMAP_HEADER_SIZE: usize = 16;
+const ENTRIES_PER_BITMAP_BLOCK: usize = (BLOCK_SIZE - BITMAP_HEADER_SIZE) * 4;
+
+fn unpack_bitmap(data: &[u8]) -> Vec<u8> {
+    let mut counts = Vec::with_capacity(ENTRIES_PER_BITMAP_BLOCK);
+    for byte in &data[BITMAP_HEADER_SIZE..] {
+        let mut b = *byte;
+        for _ in 0..4 {
+            counts.push(b & 0b11);
+            b >>= 2;
         }
     }
+    counts
+}
+
+// A mismatch between the ref count recorded in the space map and the
+// count we observed while walking the mapping tree.
+struct RefCountMismatch {
+    block: u64,
+    expected: u32,
+    actual: u32,
+}
 
-    engine.read_many(&mut blocks)?;
+// Collects the space map's index entries in key order, recursing through
+// internal nodes the same way walk_device_details does for the device
+// details tree.
+fn collect_index_entries<E: IoEngine>(
+    engine: &mut E,
+    root: u64,
+    entries: &mut Vec<SmIndexEntry>,
+) -> Result<()> {
+    let mut b = Block::new(root);
+    engine.read(&mut b)?;
+    let node = unpack_node::<ValueSmIndexEntry>(&b.get_data())?;
 
-    for b in blocks {
-        walk_node(engine, seen, level, &b);
+    match node {
+        Node::Leaf { values, .. } => entries.extend(values),
+        Node::Internal { values, .. } => {
+            for child in values {
+                collect_index_entries(engine, child, entries)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn walk_node<E: IoEngine>(
+// A mismatch between a space-map index entry's own free-space bookkeeping
+// and what's actually encoded in the ref counts of its bitmap.
+struct IndexEntryMismatch {
+    blocknr: u64,
+    field: &'static str,
+    expected: u32,
+    actual: u32,
+}
+
+// Checks one bitmap block's worth of ref counts: the per-block mismatches
+// against `observed`, plus the index entry's own nr_free/none_free_before
+// bookkeeping against what the bitmap actually encodes. `index` is this
+// bitmap's position among the space map's index entries, used to turn a
+// within-bitmap offset into an absolute metadata block number.
+fn check_bitmap_entry(
+    index: usize,
+    entry: &SmIndexEntry,
+    counts: &[u8],
+    observed: &HashMap<u64, u32>,
+) -> (Vec<RefCountMismatch>, Vec<IndexEntryMismatch>) {
+    let mut mismatches = Vec::new();
+    let mut index_mismatches = Vec::new();
+
+    let mut nr_free = 0;
+    // "none free before this offset" -- the index of the first free
+    // entry, or the bitmap's length if every entry is allocated.
+    let mut none_free_before = counts.len() as u32;
+    let mut seen_free = false;
+
+    for (offset, stored) in counts.iter().enumerate() {
+        let block = (index * ENTRIES_PER_BITMAP_BLOCK + offset) as u64;
+        let stored = *stored as u32;
+        let actual = *observed.get(&block).unwrap_or(&0);
+
+        if stored == 0 {
+            nr_free += 1;
+            if !seen_free {
+                none_free_before = offset as u32;
+                seen_free = true;
+            }
+        }
+
+        if stored != 3 && stored != actual {
+            mismatches.push(RefCountMismatch {
+                block,
+                expected: stored,
+                actual,
+            });
+        }
+    }
+
+    if entry.nr_free != nr_free {
+        index_mismatches.push(IndexEntryMismatch {
+            blocknr: entry.blocknr,
+            field: "nr_free",
+            expected: entry.nr_free,
+            actual: nr_free,
+        });
+    }
+    if entry.none_free_before != none_free_before {
+        index_mismatches.push(IndexEntryMismatch {
+            blocknr: entry.blocknr,
+            field: "none_free_before",
+            expected: entry.none_free_before,
+            actual: none_free_before,
+        });
+    }
+
+    (mismatches, index_mismatches)
+}
+
+fn check_space_map<E: IoEngine>(
+    engine: &mut E,
+    root: u64,
+    observed: &HashMap<u64, u32>,
+) -> Result<(Vec<RefCountMismatch>, Vec<IndexEntryMismatch>)> {
+    let mut index_entries = Vec::new();
+    collect_index_entries(engine, root, &mut index_entries)?;
+
+    let mut mismatches = Vec::new();
+    let mut index_mismatches = Vec::new();
+    for (index, entry) in index_entries.iter().enumerate() {
+        let mut bitmap_block = Block::new(entry.blocknr);
+        engine.read(&mut bitmap_block)?;
+        let counts = unpack_bitmap(&bitmap_block.get_data());
+
+        let (block_mismatches, entry_mismatches) =
+            check_bitmap_entry(index, entry, &counts, observed);
+        mismatches.extend(block_mismatches);
+        index_mismatches.extend(entry_mismatches);
+    }
+
+    Ok((mismatches, index_mismatches))
+}
+
+// The device-details tree is keyed by thin device id and records, among
+// other things, how many data blocks that device has mapped.
+struct DeviceDetails {
+    mapped_block_count: u64,
+    transaction_id: u64,
+    creation_time: u32,
+    snapshotted_time: u32,
+}
+
+struct ValueDeviceDetails;
+
+impl ValueType for ValueDeviceDetails {
+    type Value = DeviceDetails;
+    fn unpack(i: &[u8]) -> IResult<&[u8], DeviceDetails> {
+        let (i, mapped_block_count) = le_u64(i)?;
+        let (i, transaction_id) = le_u64(i)?;
+        let (i, creation_time) = le_u32(i)?;
+        let (i, snapshotted_time) = le_u32(i)?;
+
+        Ok((
+            i,
+            DeviceDetails {
+                mapped_block_count,
+                transaction_id,
+                creation_time,
+                snapshotted_time,
+            },
+        ))
+    }
+}
+
+// The device-details tree is small compared to the mapping tree (one
+// entry per thin device rather than per mapped block), so it isn't worth
+// running through the worker pool -- a plain recursive walk is enough.
+fn walk_device_details<E: IoEngine>(
     engine: &mut E,
-    seen: &mut HashSet<u64>,
-    level: MappingLevel,
-    b: &Block,
+    root: u64,
+    details: &mut HashMap<u64, DeviceDetails>,
 ) -> Result<()> {
-    seen.insert(b.loc);
+    let mut b = Block::new(root);
+    engine.read(&mut b)?;
 
     let bt = checksum::metadata_block_type(b.get_data());
     if bt != checksum::BT::NODE {
         return Err(anyhow!("checksum failed for node {}, {:?}", b.loc, bt));
     }
 
-    match level {
-        MappingLevel::Top => {
-            let node = unpack_node::<ValueU64>(&b.get_data())?;
-            match node {
-                Node::Leaf {
-                    header: header,
-                    keys: _keys,
-                    values,
-                } => {
-                    walk_nodes(engine, seen, MappingLevel::Bottom, &values)?;
-                }
-                Node::Internal {
-                    header: header,
-                    keys: _keys,
-                    values,
-                } => {
-                    walk_nodes(engine, seen, MappingLevel::Top, &values)?;
-                }
+    let node = unpack_node::<ValueDeviceDetails>(&b.get_data())?;
+    match node {
+        Node::Leaf { keys, values, .. } => {
+            for (dev_id, d) in keys.into_iter().zip(values.into_iter()) {
+                details.insert(dev_id, d);
             }
         }
-        MappingLevel::Bottom => {
-            let node = unpack_node::<ValueBlockTime>(&b.get_data())?;
-            match node {
-                Node::Leaf {
-                    header: header,
-                    keys: _keys,
-                    values,
-                } => {
-                    // FIXME: check in bounds
-                }
-                Node::Internal {
-                    header: header,
-                    keys: _keys,
-                    values,
-                } => {
-                    walk_nodes(engine, seen, MappingLevel::Bottom, &values)?;
-                }
+        Node::Internal { values, .. } => {
+            for child in values {
+                walk_device_details(engine, child, details)?;
             }
         }
     }
@@ -219,23 +902,241 @@ fn walk_node<E: IoEngine>(
     Ok(())
 }
 
+// A thin device whose observed mapped-block count (from walking the
+// mapping tree) disagrees with what the device-details tree records.
+struct MappedCountMismatch {
+    dev_id: u64,
+    expected: u64,
+    actual: u64,
+}
+
+fn check_mapped_counts(
+    details: &HashMap<u64, DeviceDetails>,
+    observed: &HashMap<u64, u64>,
+) -> Vec<MappedCountMismatch> {
+    let mut mismatches = Vec::new();
+    let dev_ids: HashSet<u64> = details.keys().chain(observed.keys()).copied().collect();
+    for dev_id in dev_ids {
+        // A device missing from `details` has no recorded mapped_block_count
+        // (itself a corruption -- the mapping tree references a thin device
+        // the device-details tree doesn't know about); a device missing from
+        // `observed` simply has no mappings at all. Either way, treat the
+        // absent side as 0 rather than skipping the device.
+        let expected = details.get(&dev_id).map_or(0, |d| d.mapped_block_count);
+        let actual = *observed.get(&dev_id).unwrap_or(&0);
+        if expected != actual {
+            mismatches.push(MappedCountMismatch {
+                dev_id,
+                expected,
+                actual,
+            });
+        }
+    }
+    mismatches
+}
+
+const DEFAULT_NR_THREADS: usize = 4;
+const DEFAULT_QUEUE_DEPTH: usize = 256;
+
 pub fn check(dev: &Path) -> Result<()> {
-    //let mut engine = SyncIoEngine::new(dev)?;
-    let mut engine = AsyncIoEngine::new(dev, 256)?;
+    check_with_threads(dev, DEFAULT_NR_THREADS, DEFAULT_QUEUE_DEPTH)
+}
+
+pub fn check_with_threads(dev: &Path, nr_threads: usize, queue_depth: usize) -> Result<()> {
+    let mut engine = AsyncIoEngine::new(dev, queue_depth)?;
 
     let now = Instant::now();
     let sb = read_superblock(&mut engine, SUPERBLOCK_LOCATION)?;
     eprintln!("{:?}", sb);
-    let mut seen = HashSet::new();
 
-    let mut root = Block::new(sb.mapping_root);
-    engine.read(&mut root)?;
+    let (mut engine, _seen, counts, mapped_counts, errors) = walk_mapping_tree(
+        engine,
+        sb.mapping_root,
+        sb.data_dev_size,
+        nr_threads,
+        queue_depth,
+    )?;
+    println!("read mapping tree in {} ms", now.elapsed().as_millis());
+
+    for e in &errors {
+        println!("{:?}", e);
+    }
+    if errors.is_empty() {
+        println!("mapping tree is well formed");
+    }
+
+    let (mismatches, index_mismatches) =
+        check_space_map(&mut engine, sb.metadata_sm_root, &counts)?;
+    for m in &mismatches {
+        println!(
+            "ref count mismatch for block {}: expected {}, got {}",
+            m.block, m.expected, m.actual
+        );
+    }
+    if mismatches.is_empty() {
+        println!("space map reference counts match");
+    }
+    for m in &index_mismatches {
+        println!(
+            "space map index entry for block {} has a bad {}: expected {}, got {}",
+            m.blocknr, m.field, m.expected, m.actual
+        );
+    }
 
-    walk_node(&mut engine, &mut seen, MappingLevel::Top, &root)?;
-    println!(
-        "read mapping tree in {} ms",
-        now.elapsed().as_millis()
-    );
+    let mut details = HashMap::new();
+    walk_device_details(&mut engine, sb.device_details_root, &mut details)?;
+    let dev_mismatches = check_mapped_counts(&details, &mapped_counts);
+    for m in &dev_mismatches {
+        println!(
+            "device {} mapped block count mismatch: expected {}, got {}",
+            m.dev_id, m.expected, m.actual
+        );
+    }
+    if dev_mismatches.is_empty() {
+        println!("device mapped block counts match");
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(is_leaf: bool, nr_entries: u32, max_entries: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0u32.to_le_bytes()); // csum
+        data.extend_from_slice(&(if is_leaf { LEAF_NODE } else { INTERNAL_NODE }).to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes()); // block
+        data.extend_from_slice(&nr_entries.to_le_bytes());
+        data.extend_from_slice(&max_entries.to_le_bytes());
+        data.extend_from_slice(&8u32.to_le_bytes()); // value_size
+        data.extend_from_slice(&0u32.to_le_bytes()); // padding
+        data
+    }
+
+    #[test]
+    fn keys_must_be_strictly_increasing() {
+        let mut errors = Vec::new();
+        check_keys_ordered(0, &[1, 2, 2, 3], &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, ErrorKind::KeysNotOrdered));
+
+        let mut errors = Vec::new();
+        check_keys_ordered(0, &[1, 2, 3], &mut errors);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn header_invariants_catch_more_entries_than_room_for() {
+        let data = header_bytes(true, 5, 3);
+        let err = check_header_invariants(0, &data).unwrap();
+        assert!(matches!(
+            err.kind,
+            ErrorKind::TooManyEntries {
+                nr_entries: 5,
+                max_entries: 3
+            }
+        ));
+
+        let data = header_bytes(true, 3, 5);
+        assert!(check_header_invariants(0, &data).is_none());
+    }
+
+    #[test]
+    fn unpack_node_rejects_corrupt_entry_counts_instead_of_panicking() {
+        let data = header_bytes(true, 5, 3);
+        assert!(unpack_node_::<ValueU64>(&data).is_err());
+    }
+
+    #[test]
+    fn unpack_bitmap_preserves_overflow_marker() {
+        let mut data = vec![0u8; BLOCK_SIZE];
+        // Packs ref counts 0, 1, 2, 3 (low bits first) into one byte.
+        data[BITMAP_HEADER_SIZE] = 0b11_10_01_00;
+        let counts = unpack_bitmap(&data);
+        assert_eq!(&counts[0..4], &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn mapped_counts_reports_mismatch_for_dev_id_missing_from_either_map() {
+        let mut details = HashMap::new();
+        details.insert(
+            1,
+            DeviceDetails {
+                mapped_block_count: 7,
+                transaction_id: 0,
+                creation_time: 0,
+                snapshotted_time: 0,
+            },
+        );
+
+        let mut observed = HashMap::new();
+        // dev 1 has no mappings observed; dev 2 was seen while walking the
+        // mapping tree but has no device-details entry at all.
+        observed.insert(2, 4);
+
+        let mut mismatches = check_mapped_counts(&details, &observed);
+        mismatches.sort_by_key(|m| m.dev_id);
+
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].dev_id, 1);
+        assert_eq!(mismatches[0].expected, 7);
+        assert_eq!(mismatches[0].actual, 0);
+        assert_eq!(mismatches[1].dev_id, 2);
+        assert_eq!(mismatches[1].expected, 0);
+        assert_eq!(mismatches[1].actual, 4);
+    }
+
+    #[test]
+    fn data_block_bounds_catches_blocks_at_or_past_the_data_device_size() {
+        let keys = vec![0, 1, 2];
+        let values = vec![
+            BlockTime { block: 0, time: 0 },
+            BlockTime { block: 9, time: 0 },
+            BlockTime { block: 10, time: 0 },
+        ];
+
+        let mut errors = Vec::new();
+        check_data_block_bounds(0, &keys, &values, 10, &mut errors);
+        assert_eq!(errors.len(), 1);
+        match &errors[0].kind {
+            ErrorKind::DataBlockOutOfBounds { nr_data_blocks } => assert_eq!(*nr_data_blocks, 10),
+            other => panic!("unexpected error kind: {:?}", other),
+        }
+        assert_eq!(errors[0].key, Some(2));
+        assert_eq!(errors[0].value, Some(10));
+    }
+
+    #[test]
+    fn bitmap_entry_reports_none_free_before_as_first_free_offset_not_first_used_one() {
+        let entry = SmIndexEntry {
+            blocknr: 42,
+            nr_free: 2,
+            none_free_before: 1,
+        };
+        // offset 0 is allocated, offsets 1 and 2 are free: a perfectly
+        // healthy, partially-used bitmap should report no mismatches.
+        let counts = vec![1u8, 0, 0];
+        let mut observed = HashMap::new();
+        observed.insert(0, 1);
+        let (mismatches, index_mismatches) = check_bitmap_entry(0, &entry, &counts, &observed);
+        assert!(mismatches.is_empty());
+        assert!(index_mismatches.is_empty());
+    }
+
+    #[test]
+    fn bitmap_entry_flags_a_genuinely_wrong_none_free_before() {
+        let entry = SmIndexEntry {
+            blocknr: 42,
+            nr_free: 2,
+            none_free_before: 0, // wrong: offset 0 is allocated
+        };
+        let counts = vec![1u8, 0, 0];
+        let (_, index_mismatches) = check_bitmap_entry(0, &entry, &counts, &HashMap::new());
+        assert_eq!(index_mismatches.len(), 1);
+        assert_eq!(index_mismatches[0].field, "none_free_before");
+        assert_eq!(index_mismatches[0].expected, 0);
+        assert_eq!(index_mismatches[0].actual, 1);
+    }
+}